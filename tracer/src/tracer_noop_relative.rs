@@ -3,15 +3,113 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::path::Path;
+
 #[macro_export]
 macro_rules! trace_relative_scoped {
-    ($event:expr, $size:expr) => {};
+    ($category:expr, $event:expr, $size:expr, $plug:expr) => {};
 }
 
 #[macro_export]
 macro_rules! trace_relative_point {
-    ($event:expr) => {};
+    ($category:expr, $event:expr) => {};
+}
+
+#[macro_export]
+macro_rules! trace_relative_counter {
+    ($category:expr, $name:expr, $value:expr) => {};
+}
+
+// The macros above expand to nothing, but the streaming/journal/span types and
+// the `set_tracer` entry point are plain functions a subsystem may reference
+// outside any macro, so they need no-op counterparts here or the crate fails to
+// compile with the `tracing-relative` feature off.
+
+/// No-op mirror of the recorded event carried by the [`Trace`] backend.
+#[derive(Clone, Debug, Default)]
+pub struct TraceEvent;
+
+/// No-op mirror of the pluggable tracing backend trait.
+pub trait Trace: Send + Sync {
+    fn record_instant(&self, _event: TraceEvent) {}
+    fn record_duration(&self, _event: TraceEvent) {}
+    fn record_counter(&self, _event: TraceEvent) {}
+    fn end(&self) {}
+}
+
+/// No-op mirror of the span identifier.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SpanId(u64);
+
+/// No-op mirror of a span that can outlive the thread it began on.
+pub struct Span;
+
+impl Span {
+    pub fn new(_category: &'static str, _event: &'static str, _size: u64, _plug: bool) -> Self {
+        Span
+    }
+
+    pub fn id(&self) -> SpanId {
+        SpanId(0)
+    }
+
+    pub fn enter(&self) -> EnteredSpan {
+        EnteredSpan
+    }
+
+    pub fn finish(self) {}
+}
+
+/// No-op mirror of the guard returned by [`Span::enter`].
+pub struct EnteredSpan;
+
+/// No-op mirror of the InfluxDB streaming backend configuration.
+pub struct InfluxConfig {
+    pub url: String,
+    pub database: String,
+}
+
+/// No-op mirror of the InfluxDB streaming backend.
+pub struct InfluxTracer;
+
+impl InfluxTracer {
+    pub fn new(_config: InfluxConfig) -> Self {
+        InfluxTracer
+    }
+}
+
+impl Trace for InfluxTracer {}
+
+/// No-op mirror of a persisted journal event.
+#[derive(Clone, Debug, Default)]
+pub struct JournalEvent;
+
+/// No-op mirror of the crash-safe journal backend.
+pub struct JournalTracer;
+
+impl JournalTracer {
+    pub fn new(_path: &Path) -> std::io::Result<Self> {
+        Ok(JournalTracer)
+    }
+}
+
+impl Trace for JournalTracer {}
+
+/// No-op mirror of the journal reader.
+pub fn read_journal(_path: &Path) -> std::io::Result<Vec<JournalEvent>> {
+    Ok(Vec::new())
+}
+
+/// No-op mirror of the journal-to-Chrome converter.
+pub fn journal_to_chrome(_path: &Path) -> std::io::Result<String> {
+    Ok(String::new())
 }
 
+pub fn enable_category(_category: &str) {}
+pub fn disable_category(_category: &str) {}
+pub fn category_enabled(_category: &str) -> bool {
+    false
+}
+pub fn set_tracer(_tracer: Box<dyn Trace>) {}
 pub fn end_relative() {}
-pub fn start_relative() {}
\ No newline at end of file
+pub fn start_relative(_influx: Option<InfluxConfig>) {}
@@ -3,146 +3,858 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use once_cell::sync::Lazy;
 use once_cell::unsync::OnceCell;
-use serde::Serialize;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
-use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::ptr::addr_of;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+/// Backend that records the events produced by the tracing macros.
+///
+/// The default [`Tracer`] accumulates everything in memory and dumps it at
+/// shutdown, but subsystems (block, net, mem, vcpu) can install their own via
+/// [`set_tracer`] to stream events elsewhere.
+pub trait Trace: Send + Sync {
+    fn record_instant(&self, event: TraceEvent);
+    fn record_duration(&self, event: TraceEvent);
+    fn record_counter(&self, event: TraceEvent);
+    /// Flush and finalize the backend. Called once from [`end_relative`].
+    fn end(&self) {}
+}
+
 #[derive(Debug)]
 struct Tracer {
     events: Arc<Mutex<HashMap<String, Vec<TraceEvent>>>>,
-    thread_depths: HashMap<String, Arc<AtomicU64>>,
-    start: Instant,
+    // Last recorded value for every counter series, keyed by (thread, name), so
+    // each new sample can be stored with the delta from its predecessor.
+    counters: Mutex<HashMap<(String, &'static str), f64>>,
 }
 
 impl Tracer {
     fn new() -> Self {
         Self {
             events: Arc::new(Mutex::new(HashMap::default())),
-            start: Instant::now(),
-            thread_depths: HashMap::default(),
+            counters: Mutex::new(HashMap::default()),
+        }
+    }
+
+    fn add_event(&self, event: TraceEvent) {
+        let current = std::thread::current();
+        let thread_name = current.name().unwrap_or("");
+        let mut events = self.events.lock().unwrap();
+        if let Some(thread_events) = events.get_mut(thread_name) {
+            thread_events.push(event);
+        } else {
+            events.insert(thread_name.to_string(), vec![event]);
+        }
+    }
+
+    // Serialize the accumulated events into the Chrome Trace Event Format, so
+    // the `.virtio-mem-trace` file opens directly in chrome://tracing or
+    // Perfetto. Each scoped `TraceBlock` becomes a complete ("X") duration
+    // event and each instant point becomes an instant ("i") event; the
+    // per-thread buckets map onto the `tid` tracks.
+    fn chrome_trace(&self) -> ChromeTrace {
+        // SAFETY: FFI call
+        let pid = unsafe { libc::getpid() };
+        let events = self.events.lock().unwrap();
+        let mut trace_events = Vec::new();
+        for (thread_name, thread_events) in events.iter() {
+            let tid = hash_thread_name(thread_name);
+            for event in thread_events {
+                trace_events.push(chrome_event(
+                    pid,
+                    tid,
+                    event.category.to_string(),
+                    event.event.to_string(),
+                    event.start_timestamp,
+                    event.end_timestamp,
+                    event.size,
+                    event.plug,
+                    event
+                        .counter_name
+                        .zip(event.counter_value)
+                        .map(|(name, value)| (name.to_string(), value, event.counter_delta)),
+                ));
+            }
+        }
+        ChromeTrace {
+            trace_events,
+            display_time_unit: "ns",
         }
     }
+}
+
+impl Trace for Tracer {
+    fn record_instant(&self, event: TraceEvent) {
+        self.add_event(event);
+    }
+
+    fn record_duration(&self, event: TraceEvent) {
+        self.add_event(event);
+    }
+
+    fn record_counter(&self, mut event: TraceEvent) {
+        // Difference this sample against the previous value for the same
+        // (thread, counter) so consumers get the delta alongside the absolute
+        // value without re-deriving it from the full series.
+        if let (Some(name), Some(value)) = (event.counter_name, event.counter_value) {
+            let current = std::thread::current();
+            let thread_name = current.name().unwrap_or("").to_string();
+            let mut counters = self.counters.lock().unwrap();
+            let previous = counters.insert((thread_name, name), value);
+            event.counter_delta = Some(value - previous.unwrap_or(0.0));
+        }
+        self.add_event(event);
+    }
 
     fn end(&self) {
-        let end = Instant::now();
         // SAFETY: FFI call
         let path = format!("cloud-hypervisor-{}.virtio-mem-trace", unsafe {
             libc::getpid()
         });
         let mut file = File::create(&path).unwrap();
 
-        #[derive(Serialize)]
-        struct TraceReport {
-            duration: Duration,
-            events: Arc<Mutex<HashMap<String, Vec<TraceEvent>>>>,
-        }
-
-        let trace_report = TraceReport {
-            duration: end.duration_since(self.start),
-            events: self.events.clone(),
-        };
-
-        serde_json::to_writer_pretty(&file, &trace_report).unwrap();
+        serde_json::to_writer_pretty(&file, &self.chrome_trace()).unwrap();
 
         file.flush().unwrap();
 
         warn!("Trace output: {}", path);
     }
+}
 
-    fn add_event(&mut self, event: TraceEvent) {
-        let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        let mut events = self.events.lock().unwrap();
-        if let Some(thread_events) = events.get_mut(thread_name) {
-            thread_events.push(event);
-        } else {
-            events.insert(thread_name.to_string(), vec![event]);
+/// Connection details for the [`InfluxTracer`] streaming backend.
+pub struct InfluxConfig {
+    /// Base URL of the InfluxDB HTTP API, e.g. `http://localhost:8086`.
+    pub url: String,
+    /// Target database passed as the `db` query parameter.
+    pub database: String,
+}
+
+// The event plus the name of the thread that produced it, captured on the
+// producing thread so the writer can tag the line-protocol record correctly.
+struct StreamEvent {
+    thread: String,
+    event: TraceEvent,
+}
+
+const CHANNEL_CAPACITY: usize = 8192;
+const BATCH_SIZE: usize = 256;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Streaming backend that forwards events to InfluxDB as they happen, so a VM
+/// that is killed or wedged still yields a live trace. Events are pushed onto a
+/// bounded channel and drained by a background writer that batches them into
+/// line-protocol HTTP writes. Opt in by passing the config to
+/// [`start_relative`]:
+///
+/// ```no_run
+/// # use tracer::{start_relative, InfluxConfig};
+/// start_relative(Some(InfluxConfig {
+///     url: "http://localhost:8086".to_string(),
+///     database: "cloud_hypervisor".to_string(),
+/// }));
+/// ```
+pub struct InfluxTracer {
+    sender: Mutex<Option<Sender<StreamEvent>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl InfluxTracer {
+    pub fn new(config: InfluxConfig) -> Self {
+        let (sender, receiver) = bounded::<StreamEvent>(CHANNEL_CAPACITY);
+        let handle = std::thread::Builder::new()
+            .name("tracer-influxdb".to_string())
+            .spawn(move || writer_loop(config, receiver))
+            .unwrap();
+        Self {
+            sender: Mutex::new(Some(sender)),
+            handle: Mutex::new(Some(handle)),
         }
     }
 
-    fn increase_thread_depth(&mut self) {
+    fn send(&self, event: TraceEvent) {
         let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        if let Some(depth) = self.thread_depths.get_mut(thread_name) {
-            depth.fetch_add(1, Ordering::SeqCst);
-        } else {
-            self.thread_depths
-                .insert(thread_name.to_string(), Arc::new(AtomicU64::new(0)));
+        let thread = current.name().unwrap_or("").to_string();
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            // Never block a hot device thread: drop the event if the writer
+            // has fallen behind and the channel is full.
+            let _ = sender.try_send(StreamEvent { thread, event });
         }
     }
+}
 
-    fn decrease_thread_depth(&mut self) {
-        let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        if let Some(depth) = self.thread_depths.get_mut(thread_name) {
-            depth.fetch_sub(1, Ordering::SeqCst);
+impl Trace for InfluxTracer {
+    fn record_instant(&self, event: TraceEvent) {
+        self.send(event);
+    }
+
+    fn record_duration(&self, event: TraceEvent) {
+        self.send(event);
+    }
+
+    fn record_counter(&self, event: TraceEvent) {
+        self.send(event);
+    }
+
+    fn end(&self) {
+        // Dropping the sender disconnects the channel and lets the writer
+        // drain its backlog and exit.
+        self.sender.lock().unwrap().take();
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Wall-clock origin for the monotonic timestamps carried on events. The
+// monotonic clock counts from an arbitrary point (boot), so it is captured
+// alongside a `CLOCK_REALTIME` reading once at writer start and used to map
+// each event's monotonic reading back onto Unix-epoch nanoseconds, which is
+// what InfluxDB expects.
+struct TimeAnchor {
+    realtime_ns: u128,
+    monotonic_ns: u128,
+}
+
+impl TimeAnchor {
+    fn now() -> Self {
+        Self {
+            realtime_ns: realtime_clock(),
+            monotonic_ns: monotonic_clock(),
+        }
+    }
+
+    fn to_realtime(&self, monotonic_ns: u128) -> u128 {
+        self.realtime_ns + monotonic_ns.saturating_sub(self.monotonic_ns)
+    }
+}
+
+fn writer_loop(config: InfluxConfig, receiver: Receiver<StreamEvent>) {
+    let anchor = TimeAnchor::now();
+    let mut batch: Vec<StreamEvent> = Vec::new();
+    let mut last_flush = Instant::now();
+    loop {
+        // Shrink the wait to the time left in the current interval so the batch
+        // is flushed periodically even under a steady sub-batch trickle that
+        // would otherwise keep resetting a plain `recv_timeout(FLUSH_INTERVAL)`.
+        let timeout = FLUSH_INTERVAL
+            .checked_sub(last_flush.elapsed())
+            .unwrap_or(Duration::ZERO);
+        match receiver.recv_timeout(timeout) {
+            Ok(event) => {
+                batch.push(event);
+                if batch.len() >= BATCH_SIZE || last_flush.elapsed() >= FLUSH_INTERVAL {
+                    flush(&config, &anchor, &mut batch);
+                    last_flush = Instant::now();
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&config, &anchor, &mut batch);
+                last_flush = Instant::now();
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                flush(&config, &anchor, &mut batch);
+                break;
+            }
+        }
+    }
+}
+
+fn flush(config: &InfluxConfig, anchor: &TimeAnchor, batch: &mut Vec<StreamEvent>) {
+    if batch.is_empty() {
+        return;
+    }
+    let mut body = String::new();
+    for StreamEvent { thread, event } in batch.iter() {
+        let size = event.size.unwrap_or(0);
+        let plug = event.plug.unwrap_or(false);
+        // Only scoped events carry a start timestamp; instant and counter
+        // points leave it at 0, so `end - start` there would be the absolute
+        // clock rather than a duration. Emit 0 for those.
+        let dur_ns = if event.start_timestamp != 0 {
+            event.end_timestamp.saturating_sub(event.start_timestamp)
         } else {
-            panic!("Unmatched decrease for thread: {thread_name}");
+            0
+        };
+        let ts = anchor.to_realtime(event.end_timestamp);
+        // Counter samples carry their continuous metric in counter_value, not
+        // in size/dur; emit it as its own field so the series is plottable in
+        // Grafana rather than arriving as a constant zero. A delta field is
+        // added only when the event carries one; `InfluxTracer` does not
+        // compute deltas, so events it records have none.
+        let mut counter_fields = String::new();
+        if let (Some(name), Some(value)) = (event.counter_name, event.counter_value) {
+            let _ = write!(counter_fields, ",counter_{name}={value}");
+            if let Some(delta) = event.counter_delta {
+                let _ = write!(counter_fields, ",counter_{name}_delta={delta}");
+            }
         }
+        let _ = writeln!(
+            body,
+            "virtio_mem,thread={thread},event={} \
+             size={size}u,plug={plug},dur_ns={dur_ns}i{counter_fields} {ts}",
+            event.event,
+        );
     }
+    if let Err(e) = post(config, &body) {
+        warn!("Failed to stream trace events to InfluxDB: {e}");
+    }
+    batch.clear();
+}
+
+fn post(config: &InfluxConfig, body: &str) -> std::io::Result<()> {
+    let (host, port, path_prefix) = parse_url(&config.url);
+    let path = format!("{path_prefix}/write?db={}", config.database);
+    let mut stream = TcpStream::connect((host.as_str(), port))?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}:{port}\r\n\
+         Content-Type: application/octet-stream\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn parse_url(url: &str) -> (String, u16, String) {
+    let rest = url.strip_prefix("http://").unwrap_or(url);
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) => (h.to_string(), p.parse().unwrap_or(8086)),
+        None => (authority.to_string(), 8086),
+    };
+    (host, port, path.trim_end_matches('/').to_string())
+}
+
+// Magic word prefixing every journal record ("VMTJ"). The reader stops at the
+// first record whose magic does not match, treating it as a torn tail; it does
+// not scan ahead to resynchronize.
+const JOURNAL_MAGIC: u32 = 0x564d_544a;
+// Fixed per-record header: magic + payload length + CRC32 of the payload.
+const JOURNAL_HEADER_LEN: usize = 12;
+// Number of appended records between durability barriers.
+const JOURNAL_SYNC_INTERVAL: usize = 64;
+
+/// Owned snapshot of a [`TraceEvent`] as it is persisted in the journal.
+///
+/// [`TraceEvent`] borrows its category/event strings as `&'static str`, which
+/// cannot be deserialized from an owned buffer, so the journal stores this
+/// owned mirror instead. It also carries the producing thread name so the
+/// converter can rebuild the per-thread `tid` tracks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEvent {
+    pub thread: String,
+    pub category: String,
+    pub event: String,
+    pub timestamp: Duration,
+    pub start_timestamp: u128,
+    pub end_timestamp: u128,
+    pub depth: u64,
+    pub size: Option<u64>,
+    pub plug: Option<bool>,
+    pub counter_name: Option<String>,
+    pub counter_value: Option<f64>,
+    pub counter_delta: Option<f64>,
+    pub span_id: SpanId,
+    pub parent_span_id: Option<SpanId>,
+    pub start_tid: u64,
+    pub end_tid: u64,
+}
 
-    fn thread_depth(&self) -> u64 {
+impl JournalEvent {
+    fn from_event(thread: String, event: &TraceEvent) -> Self {
+        Self {
+            thread,
+            category: event.category.to_string(),
+            event: event.event.to_string(),
+            timestamp: event.timestamp,
+            start_timestamp: event.start_timestamp,
+            end_timestamp: event.end_timestamp,
+            depth: event.depth,
+            size: event.size,
+            plug: event.plug,
+            counter_name: event.counter_name.map(str::to_string),
+            counter_value: event.counter_value,
+            counter_delta: event.counter_delta,
+            span_id: event.span_id,
+            parent_span_id: event.parent_span_id,
+            start_tid: event.start_tid,
+            end_tid: event.end_tid,
+        }
+    }
+}
+
+// Append side of the journal: an open file plus a count of records written
+// since the last durability barrier.
+struct JournalWriter {
+    file: File,
+    since_sync: usize,
+}
+
+impl JournalWriter {
+    // Append one length-prefixed, CRC32-checksummed record and fsync every
+    // `JOURNAL_SYNC_INTERVAL` records so a crash loses at most that many.
+    fn write_record(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        let crc = crc32fast::hash(payload);
+        // Assemble the header and payload and emit them in a single write so a
+        // hot recording thread pays one syscall per event rather than four.
+        let mut record = Vec::with_capacity(JOURNAL_HEADER_LEN + payload.len());
+        record.extend_from_slice(&JOURNAL_MAGIC.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(payload);
+        self.file.write_all(&record)?;
+        self.since_sync += 1;
+        if self.since_sync >= JOURNAL_SYNC_INTERVAL {
+            self.file.sync_data()?;
+            self.since_sync = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Crash-safe backend that appends every event to an on-disk journal as it
+/// happens, so a panic, OOM-kill, or hypervisor crash still leaves behind every
+/// event that was durably flushed. Each record is a fixed header (magic +
+/// length + CRC32) followed by the bincode-encoded [`JournalEvent`]. Install
+/// with [`set_tracer`] before [`start_relative`] to opt in; convert a journal
+/// back to the Chrome format with [`journal_to_chrome`].
+pub struct JournalTracer {
+    writer: Mutex<JournalWriter>,
+}
+
+impl JournalTracer {
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            writer: Mutex::new(JournalWriter {
+                file,
+                since_sync: 0,
+            }),
+        })
+    }
+
+    fn append(&self, event: TraceEvent) {
         let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        self.thread_depths
-            .get(thread_name)
-            .map(|v| v.load(Ordering::SeqCst))
-            .unwrap_or_default()
+        let thread = current.name().unwrap_or("").to_string();
+        let record = JournalEvent::from_event(thread, &event);
+        let payload = match bincode::serialize(&record) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to encode trace event for journal: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.writer.lock().unwrap().write_record(&payload) {
+            warn!("Failed to append trace event to journal: {e}");
+        }
+    }
+}
+
+impl Trace for JournalTracer {
+    fn record_instant(&self, event: TraceEvent) {
+        self.append(event);
+    }
+
+    fn record_duration(&self, event: TraceEvent) {
+        self.append(event);
+    }
+
+    fn record_counter(&self, event: TraceEvent) {
+        self.append(event);
+    }
+
+    fn end(&self) {
+        let _ = self.writer.lock().unwrap().file.sync_all();
     }
 }
 
-static mut TRACER: OnceCell<Tracer> = OnceCell::new();
+/// Walk a journal sequentially, validating each record's CRC32, and return
+/// every durably flushed event. A torn or corrupt record - including a
+/// truncated tail left by a crashed VMM - is treated as end-of-stream rather
+/// than an error, so a partially written journal still yields all the events
+/// that made it to disk before the crash.
+pub fn read_journal(path: &Path) -> std::io::Result<Vec<JournalEvent>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + JOURNAL_HEADER_LEN <= bytes.len() {
+        let magic = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        if magic != JOURNAL_MAGIC {
+            break;
+        }
+        let len = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into().unwrap());
+        let start = offset + JOURNAL_HEADER_LEN;
+        let Some(end) = start.checked_add(len).filter(|end| *end <= bytes.len()) else {
+            // Truncated tail: the header promised more payload than was flushed.
+            break;
+        };
+        let payload = &bytes[start..end];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        match bincode::deserialize::<JournalEvent>(payload) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+        offset = end;
+    }
+    Ok(events)
+}
+
+/// Read a journal and re-emit it in the Chrome Trace Event Format, identical to
+/// what [`Tracer::end`] writes, so a journal recovered from a crashed VMM can be
+/// opened directly in chrome://tracing or Perfetto.
+pub fn journal_to_chrome(path: &Path) -> std::io::Result<String> {
+    // SAFETY: FFI call
+    let pid = unsafe { libc::getpid() };
+    let events = read_journal(path)?;
+    let mut trace_events = Vec::new();
+    for event in &events {
+        trace_events.push(chrome_event(
+            pid,
+            hash_thread_name(&event.thread),
+            event.category.clone(),
+            event.event.clone(),
+            event.start_timestamp,
+            event.end_timestamp,
+            event.size,
+            event.plug,
+            event
+                .counter_name
+                .clone()
+                .zip(event.counter_value)
+                .map(|(name, value)| (name, value, event.counter_delta)),
+        ));
+    }
+    let trace = ChromeTrace {
+        trace_events,
+        display_time_unit: "ns",
+    };
+    Ok(serde_json::to_string_pretty(&trace).unwrap())
+}
+
+// SAFETY: TRACER and START are set once from start_relative()/set_tracer()
+// before any other thread starts, and only read afterwards.
+static mut TRACER: OnceCell<Box<dyn Trace>> = OnceCell::new();
+static mut START: OnceCell<Instant> = OnceCell::new();
+
+thread_local!(static THREAD_DEPTH: Cell<u64> = const { Cell::new(0) });
+
+// The span currently in scope on this thread, used as the parent of the next
+// span opened here. Restored when a scope closes or an `EnteredSpan` is dropped.
+thread_local!(static CURRENT_SPAN: Cell<Option<SpanId>> = const { Cell::new(None) });
+
+// The set of categories whose events are currently being recorded. The
+// `TRACING_ENABLED` flag short-circuits the common all-disabled path so the
+// macros pay nothing until at least one category is turned on.
+static TRACING_ENABLED: AtomicBool = AtomicBool::new(false);
+static ENABLED_CATEGORIES: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Start recording events for `category`.
+pub fn enable_category(category: &str) {
+    ENABLED_CATEGORIES
+        .lock()
+        .unwrap()
+        .insert(category.to_string());
+    TRACING_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop recording events for `category`.
+pub fn disable_category(category: &str) {
+    let mut categories = ENABLED_CATEGORIES.lock().unwrap();
+    categories.remove(category);
+    if categories.is_empty() {
+        TRACING_ENABLED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Cheap check used by the macros to skip tracing work for a disabled
+/// category without taking the lock in the fully-disabled case.
+pub fn category_enabled(category: &str) -> bool {
+    if !TRACING_ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    ENABLED_CATEGORIES.lock().unwrap().contains(category)
+}
+
+fn tracer() -> &'static dyn Trace {
+    // SAFETY: TRACER is set during start_relative() before other threads start.
+    // Reading through a raw pointer avoids taking a reference to the `static
+    // mut` itself (static_mut_refs).
+    unsafe { (*addr_of!(TRACER)).get().unwrap().as_ref() }
+}
+
+fn start() -> Instant {
+    // SAFETY: START is set during start_relative() before other threads start.
+    // Reading through a raw pointer avoids taking a reference to the `static
+    // mut` itself (static_mut_refs).
+    unsafe { *(*addr_of!(START)).get().unwrap() }
+}
+
+fn thread_depth() -> u64 {
+    THREAD_DEPTH.with(|depth| depth.get())
+}
+
+fn increase_thread_depth() {
+    THREAD_DEPTH.with(|depth| depth.set(depth.get() + 1));
+}
+
+fn decrease_thread_depth() {
+    THREAD_DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+}
+
+fn current_span() -> Option<SpanId> {
+    CURRENT_SPAN.with(|span| span.get())
+}
+
+fn set_current_span(span: Option<SpanId>) {
+    CURRENT_SPAN.with(|current| current.set(span));
+}
+
+// Identifier of the calling thread, hashed from its name so it matches the
+// per-thread `tid` tracks used by the Chrome export.
+fn current_tid() -> u64 {
+    let current = std::thread::current();
+    hash_thread_name(current.name().unwrap_or(""))
+}
+
+/// Opaque identifier for a trace span, allocated from a process-wide counter.
+///
+/// Spans make nesting explicit via a parent link instead of inferring it from
+/// the current OS thread's depth, so a scope can begin on one thread and end on
+/// another - across a channel or an `.await` - and still form a correct
+/// parent/child duration pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SpanId(u64);
+
+static SPAN_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+impl SpanId {
+    fn alloc() -> Self {
+        SpanId(SPAN_COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
 #[derive(Clone, Debug, Serialize)]
-struct TraceEvent {
-    timestamp: Duration,
-    event: &'static str,
+pub struct TraceEvent {
+    pub category: &'static str,
+    pub timestamp: Duration,
+    pub event: &'static str,
+    // Monotonic clock reading (ns) taken when the scope opened; 0 for
+    // instant points, where only `end_timestamp` is meaningful.
+    pub start_timestamp: u128,
+    pub end_timestamp: u128,
+    pub depth: u64,
+    pub size: Option<u64>,
+    pub plug: Option<bool>,
+    // Counter series name and sample value; set only for counter events,
+    // which are point samples and therefore ignore the `depth` nesting.
+    pub counter_name: Option<&'static str>,
+    pub counter_value: Option<f64>,
+    // Change from this counter's previous sample on the same thread, filled in
+    // by the recording backend from its per-thread last-value map. `None` until
+    // a backend that tracks last values records it.
+    pub counter_delta: Option<f64>,
+    // Span identity and parent link, decoupling nesting from the current OS
+    // thread so a scope that migrates between threads still nests correctly.
+    pub span_id: SpanId,
+    pub parent_span_id: Option<SpanId>,
+    // Thread that opened the scope and the one that closed it; they differ when
+    // work moves across a channel or an `.await`.
+    pub start_tid: u64,
+    pub end_tid: u64,
+}
+
+#[derive(Serialize)]
+struct ChromeTrace {
+    #[serde(rename = "traceEvents")]
+    trace_events: Vec<ChromeEvent>,
+    #[serde(rename = "displayTimeUnit")]
+    display_time_unit: &'static str,
+}
+
+#[derive(Serialize)]
+struct ChromeEvent {
+    name: String,
+    cat: String,
+    ph: &'static str,
+    ts: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u128>,
+    pid: i32,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    args: serde_json::Value,
+}
+
+// Build a single Chrome trace event from a recorded event's fields. Shared by
+// the in-memory dump and the journal converter so both emit identical JSON.
+#[allow(clippy::too_many_arguments)]
+fn chrome_event(
+    pid: i32,
+    tid: u64,
+    category: String,
+    name: String,
+    start_timestamp: u128,
     end_timestamp: u128,
-    depth: u64,
     size: Option<u64>,
-    plug: Option<bool>
+    plug: Option<bool>,
+    counter: Option<(String, f64, Option<f64>)>,
+) -> ChromeEvent {
+    if let Some((counter_name, value, delta)) = counter {
+        // Counter sample: a named series value plotted over time, plus the
+        // change since the previous sample when the backend tracked it.
+        let mut args = serde_json::json!({ counter_name.clone(): value });
+        if let Some(delta) = delta {
+            args[format!("{counter_name}.delta")] = serde_json::json!(delta);
+        }
+        return ChromeEvent {
+            name,
+            cat: category,
+            ph: "C",
+            ts: end_timestamp / 1_000,
+            dur: None,
+            pid,
+            tid,
+            s: None,
+            args,
+        };
+    }
+    let args = serde_json::json!({ "size": size, "plug": plug });
+    if start_timestamp == 0 {
+        // Instant point: only the completion timestamp is known.
+        ChromeEvent {
+            name,
+            cat: category,
+            ph: "i",
+            ts: end_timestamp / 1_000,
+            dur: None,
+            pid,
+            tid,
+            s: Some("t"),
+            args,
+        }
+    } else {
+        // Complete duration event spanning [start, end].
+        ChromeEvent {
+            name,
+            cat: category,
+            ph: "X",
+            ts: start_timestamp / 1_000,
+            dur: Some(end_timestamp.saturating_sub(start_timestamp) / 1_000),
+            pid,
+            tid,
+            s: None,
+            args,
+        }
+    }
 }
 
-pub fn trace_point_log(event: &'static str) {
+fn hash_thread_name(thread_name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    thread_name.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn trace_point_log(category: &'static str, event: &'static str) {
     let trace_event = TraceEvent {
-        // SAFETY: start has been initialised as part of initialising the value of TRACER
-        timestamp: Instant::now().duration_since(unsafe { TRACER.get().unwrap().start }),
+        category,
+        timestamp: Instant::now().duration_since(start()),
         event,
-        end_timestamp: 0,
-        // SAFETY: thread_depth accesses current thread only specific data
-        depth: unsafe { TRACER.get().unwrap().thread_depth() },
+        start_timestamp: 0,
+        end_timestamp: monotonic_clock(),
+        depth: thread_depth(),
         size: None,
-        plug: None
+        plug: None,
+        counter_name: None,
+        counter_value: None,
+        counter_delta: None,
+        span_id: SpanId::alloc(),
+        parent_span_id: current_span(),
+        start_tid: current_tid(),
+        end_tid: current_tid(),
     };
-    // SAFETY: add_event accesses current thread only specific data
-    unsafe {
-        TRACER.get_mut().unwrap().add_event(trace_event);
-    }
+    tracer().record_instant(trace_event);
+}
+
+pub fn trace_counter_log(category: &'static str, name: &'static str, value: f64) {
+    let trace_event = TraceEvent {
+        category,
+        timestamp: Instant::now().duration_since(start()),
+        event: name,
+        start_timestamp: 0,
+        end_timestamp: monotonic_clock(),
+        // Counters are point samples, not scopes, so they ignore nesting depth.
+        depth: 0,
+        size: None,
+        plug: None,
+        counter_name: Some(name),
+        counter_value: Some(value),
+        // Filled in by the recording backend from its last-value map.
+        counter_delta: None,
+        span_id: SpanId::alloc(),
+        parent_span_id: current_span(),
+        start_tid: current_tid(),
+        end_tid: current_tid(),
+    };
+    tracer().record_counter(trace_event);
 }
 
 pub struct TraceBlock {
+    category: &'static str,
     start: Instant,
+    start_timestamp: u128,
     event: &'static str,
     size: u64,
-    plug: bool
+    plug: bool,
+    span_id: SpanId,
+    parent_span_id: Option<SpanId>,
+    start_tid: u64,
 }
 
 impl TraceBlock {
-    pub fn new(event: &'static str, size: u64, plug: bool) -> Self {
-        // SAFETY: increase_thread_depth accesses current thread only specific data
-        unsafe {
-            TRACER.get_mut().unwrap().increase_thread_depth();
-        }
+    pub fn new(category: &'static str, event: &'static str, size: u64, plug: bool) -> Self {
+        increase_thread_depth();
+        let parent_span_id = current_span();
+        let span_id = SpanId::alloc();
+        set_current_span(Some(span_id));
         Self {
+            category,
             start: Instant::now(),
+            start_timestamp: monotonic_clock(),
             event,
             size,
-            plug
+            plug,
+            span_id,
+            parent_span_id,
+            start_tid: current_tid(),
         }
     }
 }
@@ -153,47 +865,190 @@ fn monotonic_clock() -> u128 {
     (ts.tv_sec() * 1_000_000_000 + ts.tv_nsec()) as u128
 }
 
+fn realtime_clock() -> u128 {
+    use nix::time;
+    let ts = time::clock_gettime(time::ClockId::CLOCK_REALTIME).unwrap();
+    (ts.tv_sec() * 1_000_000_000 + ts.tv_nsec()) as u128
+}
+
 impl Drop for TraceBlock {
     fn drop(&mut self) {
-        // SAFETY: start has been initialised as part of initialising the value of TRACER
-        let start = unsafe { TRACER.get().unwrap().start };
         let trace_event = TraceEvent {
-            timestamp: self.start.duration_since(start),
+            category: self.category,
+            timestamp: self.start.duration_since(start()),
             event: self.event,
+            start_timestamp: self.start_timestamp,
             end_timestamp: monotonic_clock(),
-            // SAFETY: thread_depth() returns a number local to the current thread
-            depth: unsafe { TRACER.get().unwrap().thread_depth() },
+            depth: thread_depth(),
             size: Some(self.size),
-            plug: Some(self.plug)
+            plug: Some(self.plug),
+            counter_name: None,
+            counter_value: None,
+        counter_delta: None,
+            span_id: self.span_id,
+            parent_span_id: self.parent_span_id,
+            start_tid: self.start_tid,
+            end_tid: current_tid(),
         };
-        // SAFETY: add_event and decrease_thread_depth access current thread only specific data
-        unsafe {
-            TRACER.get_mut().unwrap().add_event(trace_event);
-            TRACER.get_mut().unwrap().decrease_thread_depth();
+        tracer().record_duration(trace_event);
+        set_current_span(self.parent_span_id);
+        decrease_thread_depth();
+    }
+}
+
+/// A trace span that can outlive the OS thread it began on.
+///
+/// Unlike [`TraceBlock`], which opens and closes on one thread via RAII, a
+/// `Span` carries its [`SpanId`] and parent link by value, so it can be moved
+/// across a channel or held across an `.await` and completed on a different
+/// thread. The thread that created it is recorded as the originating `tid` and
+/// the thread that drops it as the completing `tid`, yielding a correct
+/// duration event even when the work migrates between threads.
+pub struct Span {
+    category: &'static str,
+    event: &'static str,
+    size: u64,
+    plug: bool,
+    start: Instant,
+    start_timestamp: u128,
+    span_id: SpanId,
+    parent_span_id: Option<SpanId>,
+    start_tid: u64,
+}
+
+impl Span {
+    /// Open a span parented on the scope currently active on this thread.
+    pub fn new(category: &'static str, event: &'static str, size: u64, plug: bool) -> Self {
+        Self {
+            category,
+            event,
+            size,
+            plug,
+            start: Instant::now(),
+            start_timestamp: monotonic_clock(),
+            span_id: SpanId::alloc(),
+            parent_span_id: current_span(),
+            start_tid: current_tid(),
         }
     }
+
+    /// This span's identifier, for correlating child work that crosses threads.
+    pub fn id(&self) -> SpanId {
+        self.span_id
+    }
+
+    /// Make this span the current parent on the calling thread until the
+    /// returned guard is dropped, so scopes opened meanwhile - including after
+    /// an `.await` that resumed on this thread - nest underneath it.
+    pub fn enter(&self) -> EnteredSpan {
+        let previous = current_span();
+        set_current_span(Some(self.span_id));
+        EnteredSpan { previous }
+    }
+
+    /// Drop the span early, recording its completed duration event at the
+    /// point of this call instead of when it would otherwise fall out of scope.
+    pub fn finish(self) {}
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let trace_event = TraceEvent {
+            category: self.category,
+            timestamp: self.start.duration_since(start()),
+            event: self.event,
+            start_timestamp: self.start_timestamp,
+            end_timestamp: monotonic_clock(),
+            // A span can complete on a different thread than it opened on, so
+            // the completing thread's `THREAD_DEPTH` is meaningless here;
+            // nesting is carried by `parent_span_id` instead.
+            depth: 0,
+            size: Some(self.size),
+            plug: Some(self.plug),
+            counter_name: None,
+            counter_value: None,
+        counter_delta: None,
+            span_id: self.span_id,
+            parent_span_id: self.parent_span_id,
+            start_tid: self.start_tid,
+            end_tid: current_tid(),
+        };
+        tracer().record_duration(trace_event);
+    }
+}
+
+/// Guard returned by [`Span::enter`] that restores the previously current span
+/// when dropped.
+pub struct EnteredSpan {
+    previous: Option<SpanId>,
+}
+
+impl Drop for EnteredSpan {
+    fn drop(&mut self) {
+        set_current_span(self.previous);
+    }
 }
 
 #[macro_export]
 macro_rules! trace_relative_point {
-    ($event:expr) => {
-        $crate::trace_point_log($event)
+    ($category:expr, $event:expr) => {
+        if $crate::category_enabled($category) {
+            $crate::trace_point_log($category, $event)
+        }
     };
 }
 
 #[macro_export]
 macro_rules! trace_relative_scoped {
-    ($event:expr, $size:expr, $plug:expr) => {
-        let _trace_scoped = $crate::TraceBlock::new($event, $size, $plug);
+    ($category:expr, $event:expr, $size:expr, $plug:expr) => {
+        let _trace_scoped = if $crate::category_enabled($category) {
+            Some($crate::TraceBlock::new($category, $event, $size, $plug))
+        } else {
+            None
+        };
+    };
+}
+
+#[macro_export]
+macro_rules! trace_relative_counter {
+    ($category:expr, $name:expr, $value:expr) => {
+        if $crate::category_enabled($category) {
+            $crate::trace_counter_log($category, $name, $value as f64)
+        }
     };
 }
 
+/// Install a custom tracing backend. Must be called before [`start_relative`].
+pub fn set_tracer(tracer: Box<dyn Trace>) {
+    // SAFETY: this is called during setup before other threads start. Going
+    // through a raw pointer avoids a reference to the `static mut` itself.
+    unsafe {
+        let _ = (*addr_of!(TRACER)).set(tracer);
+    }
+}
+
 pub fn end_relative() {
     // SAFETY: this is called after all other threads end
-    unsafe { TRACER.get().unwrap().end() }
+    tracer().end()
 }
 
-pub fn start_relative() {
-    // SAFETY: this is called before other threads start
-    unsafe { TRACER.set(Tracer::new()).unwrap() }
+/// Start recording. Pass `Some(InfluxConfig)` to stream events live to InfluxDB;
+/// pass `None` to keep the default file backend that dumps at shutdown. A
+/// backend installed earlier via [`set_tracer`] takes precedence over both.
+pub fn start_relative(influx: Option<InfluxConfig>) {
+    // SAFETY: this is called before other threads start. Going through raw
+    // pointers avoids references to the `static mut`s themselves.
+    unsafe {
+        let _ = (*addr_of!(START)).set(Instant::now());
+        if (*addr_of!(TRACER)).get().is_none() {
+            match influx {
+                Some(config) => {
+                    let _ = (*addr_of!(TRACER)).set(Box::new(InfluxTracer::new(config)));
+                }
+                None => {
+                    let _ = (*addr_of!(TRACER)).set(Box::new(Tracer::new()));
+                }
+            }
+        }
+    }
 }